@@ -1,5 +1,5 @@
 use bytes::{Buf, BytesMut};
-use httparse::{EMPTY_HEADER, Request};
+use httparse::{EMPTY_HEADER, Request, Response};
 use std::error::Error;
 
 #[derive(Debug)]
@@ -38,3 +38,147 @@ pub fn parse_http_request(
         Ok(None)
     }
 }
+
+#[derive(Debug)]
+pub struct ParsedResponse {
+    pub status_code: u16,
+    pub reason: String,
+    pub headers: Vec<(String, String)>,
+}
+
+pub fn parse_http_response(
+    buffer: &mut BytesMut,
+) -> Result<Option<ParsedResponse>, Box<dyn Error + Send + Sync>> {
+    let mut headers = [EMPTY_HEADER; 16];
+    let mut res = Response::new(&mut headers);
+
+    let status = res.parse(buffer)?;
+
+    if status.is_complete() {
+        let parsed_len = status.unwrap();
+
+        let parsed_res = ParsedResponse {
+            status_code: res.code.unwrap_or(0),
+            reason: res.reason.unwrap_or("").to_string(),
+            headers: headers
+                .iter()
+                .take_while(|h| h.name != "")
+                .map(|h| (h.name.to_string(), String::from_utf8_lossy(h.value).into_owned()))
+                .collect(),
+        };
+        buffer.advance(parsed_len);
+        Ok(Some(parsed_res))
+    } else {
+        Ok(None)
+    }
+}
+
+/// How a message body is delimited, per RFC 7230 §3.3.3.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BodyFraming {
+    /// Neither `Transfer-Encoding: chunked` nor a parsable `Content-Length` is present. A request
+    /// then has no body; a response's body, if any, runs until the connection closes.
+    None,
+    ContentLength(usize),
+    Chunked,
+}
+
+/// Whether a response to `method` with `status_code` carries no body regardless of any
+/// `Content-Length`/`Transfer-Encoding` it declares (RFC 7230 §3.3.3 rules 1-2): responses to
+/// `HEAD`, 1xx informational, `204 No Content` and `304 Not Modified`.
+pub fn response_has_no_body(method: &str, status_code: u16) -> bool {
+    method.eq_ignore_ascii_case("HEAD")
+        || (100..200).contains(&status_code)
+        || status_code == 204
+        || status_code == 304
+}
+
+/// Inspects a parsed message's headers to determine how its body is framed.
+/// `Transfer-Encoding: chunked` takes priority over `Content-Length`, per the RFC.
+pub fn determine_body_framing(headers: &[(String, String)]) -> BodyFraming {
+    let is_chunked = headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("transfer-encoding") && value.to_ascii_lowercase().contains("chunked")
+    });
+    if is_chunked {
+        return BodyFraming::Chunked;
+    }
+
+    if let Some((_, value)) = headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("content-length")) {
+        if let Ok(len) = value.trim().parse::<usize>() {
+            return BodyFraming::ContentLength(len);
+        }
+    }
+
+    BodyFraming::None
+}
+
+/// Headers that are meaningful only for the current connection (RFC 2616 §13.5.1) and must not
+/// be forwarded to the upstream as-is.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Drops hop-by-hop headers from `headers`, including any extra header named in the request's
+/// own `Connection` token list.
+pub fn strip_hop_by_hop_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let mut connection_tokens: Vec<String> = Vec::new();
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("connection") {
+            connection_tokens.extend(value.split(',').map(|token| token.trim().to_ascii_lowercase()));
+        }
+    }
+
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let lower_name = name.to_ascii_lowercase();
+            !HOP_BY_HOP_HEADERS.contains(&lower_name.as_str()) && !connection_tokens.contains(&lower_name)
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn determine_body_framing_defaults_to_none() {
+        assert_eq!(determine_body_framing(&headers(&[])), BodyFraming::None);
+    }
+
+    #[test]
+    fn determine_body_framing_reads_content_length() {
+        let h = headers(&[("Content-Length", "42")]);
+        assert_eq!(determine_body_framing(&h), BodyFraming::ContentLength(42));
+    }
+
+    #[test]
+    fn determine_body_framing_ignores_unparsable_content_length() {
+        let h = headers(&[("Content-Length", "not-a-number")]);
+        assert_eq!(determine_body_framing(&h), BodyFraming::None);
+    }
+
+    #[test]
+    fn determine_body_framing_chunked_takes_priority_over_content_length() {
+        let h = headers(&[("Content-Length", "42"), ("Transfer-Encoding", "chunked")]);
+        assert_eq!(determine_body_framing(&h), BodyFraming::Chunked);
+    }
+
+    #[test]
+    fn determine_body_framing_transfer_encoding_is_case_insensitive() {
+        let h = headers(&[("transfer-encoding", "CHUNKED")]);
+        assert_eq!(determine_body_framing(&h), BodyFraming::Chunked);
+    }
+}
@@ -1,22 +1,57 @@
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_FIRST_BYTE_TIMEOUT: Duration = Duration::from_secs(30);
 
 mod http_parser;
+mod routing;
+
+const ROUTING_CONFIG_PATH: &str = "routing.toml";
+const DEFAULT_UPSTREAM: &str = "192.168.0.40:80";
+const PROXY_PROTOCOL_ENV_VAR: &str = "PROXY_PROTOCOL_VERSION";
+
+/// Idle upstream sockets kept open for keep-alive reuse, keyed by upstream `host:port`.
+type UpstreamPool = Arc<Mutex<HashMap<String, VecDeque<TcpStream>>>>;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let routing_table = match routing::RoutingTable::load(ROUTING_CONFIG_PATH) {
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!(
+                "Could not load routing config {} ({}), falling back to default upstream {}",
+                ROUTING_CONFIG_PATH, e, DEFAULT_UPSTREAM
+            );
+            routing::RoutingTable::single_upstream(DEFAULT_UPSTREAM)
+        }
+    };
+    let upstream_pool: UpstreamPool = Arc::new(Mutex::new(HashMap::new()));
+
+    let proxy_protocol_version = std::env::var(PROXY_PROTOCOL_ENV_VAR)
+        .map(|v| ProxyProtocolVersion::from_config_str(&v))
+        .unwrap_or(ProxyProtocolVersion::None);
+    println!("PROXY protocol mode: {:?}", proxy_protocol_version);
+
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
     println!("Listening on 127.0.0.1:8080");
     loop {
         let (socket, addr) = listener.accept().await?;
         println!("Accepted connection from {}", addr);
 
+        let routing_table = Arc::clone(&routing_table);
+        let upstream_pool = Arc::clone(&upstream_pool);
         tokio::spawn(async move {
-            let mut handler = ProxyConnection::new(socket, addr);
+            let mut handler =
+                ProxyConnection::new(socket, addr, routing_table, upstream_pool, proxy_protocol_version);
             if let Err(e) = handler.handle_request().await {
                 eprintln!("Error handling request for {}: {}", addr, e);
             }
@@ -67,21 +102,155 @@ impl From<Box<dyn Error + Send + Sync>> for ProxyError {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyProtocolVersion {
+    None,
+    V1,
+    V2,
+}
+
+impl ProxyProtocolVersion {
+    /// Parses the `PROXY_PROTOCOL_VERSION` environment variable's value (`"v1"` / `"v2"`,
+    /// case-insensitive); anything else, including unset, disables the header.
+    fn from_config_str(value: &str) -> Self {
+        match value.to_ascii_lowercase().as_str() {
+            "v1" => ProxyProtocolVersion::V1,
+            "v2" => ProxyProtocolVersion::V2,
+            _ => ProxyProtocolVersion::None,
+        }
+    }
+}
+
+/// Builds a PROXY protocol header for a connection from `client_addr` to `upstream_addr`.
+/// Returns an empty buffer for `ProxyProtocolVersion::None`. Kept as a free function, independent
+/// of any live socket, so the wire format can be unit tested directly.
+fn build_proxy_protocol_header(
+    version: ProxyProtocolVersion,
+    client_addr: std::net::SocketAddr,
+    upstream_addr: std::net::SocketAddr,
+) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::None => Vec::new(),
+        ProxyProtocolVersion::V1 => {
+            let proto = if client_addr.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                proto,
+                client_addr.ip(),
+                upstream_addr.ip(),
+                client_addr.port(),
+                upstream_addr.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = Vec::with_capacity(16 + 36);
+            header.extend_from_slice(&[
+                0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+            ]);
+            match (client_addr.ip(), upstream_addr.ip()) {
+                (std::net::IpAddr::V4(src), std::net::IpAddr::V4(dst)) => {
+                    header.push(0x21); // version 2, PROXY command
+                    header.push(0x11); // AF_INET, STREAM
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                    header.extend_from_slice(&client_addr.port().to_be_bytes());
+                    header.extend_from_slice(&upstream_addr.port().to_be_bytes());
+                }
+                (std::net::IpAddr::V6(src), std::net::IpAddr::V6(dst)) => {
+                    header.push(0x21); // version 2, PROXY command
+                    header.push(0x21); // AF_INET6, STREAM
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                    header.extend_from_slice(&client_addr.port().to_be_bytes());
+                    header.extend_from_slice(&upstream_addr.port().to_be_bytes());
+                }
+                _ => {
+                    // Mixed address families: there's no accurate way to encode this, so
+                    // fall back to the protocol's LOCAL command (no address block) rather
+                    // than claim a family that doesn't match the real addresses.
+                    header.push(0x20); // version 2, LOCAL command
+                    header.push(0x00); // AF_UNSPEC, UNSPEC
+                    header.extend_from_slice(&0u16.to_be_bytes());
+                }
+            }
+            header
+        }
+    }
+}
+
 struct ProxyConnection {
     client_socket: TcpStream,
     client_addr: std::net::SocketAddr,
     client_buffer: BytesMut,
+    proxy_protocol_version: ProxyProtocolVersion,
+    routing_table: Arc<routing::RoutingTable>,
+    upstream_pool: UpstreamPool,
+    connect_timeout: Duration,
+    first_byte_timeout: Duration,
 }
 
 impl ProxyConnection {
-    fn new(client_socket: TcpStream, client_addr: std::net::SocketAddr) -> Self {
+    fn new(
+        client_socket: TcpStream,
+        client_addr: std::net::SocketAddr,
+        routing_table: Arc<routing::RoutingTable>,
+        upstream_pool: UpstreamPool,
+        proxy_protocol_version: ProxyProtocolVersion,
+    ) -> Self {
         Self {
             client_socket,
             client_addr,
             client_buffer: BytesMut::with_capacity(4096),
+            proxy_protocol_version,
+            routing_table,
+            upstream_pool,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            first_byte_timeout: DEFAULT_FIRST_BYTE_TIMEOUT,
+        }
+    }
+
+    /// Connects to `addr`, retrying exactly once if the first attempt doesn't complete within
+    /// `self.connect_timeout`. The returned error's `ErrorKind::TimedOut` distinguishes a timeout
+    /// (worth reporting as 504 Gateway Timeout) from a regular connection failure (502).
+    async fn connect_with_timeout(&self, addr: &str) -> io::Result<TcpStream> {
+        match tokio::time::timeout(self.connect_timeout, TcpStream::connect(addr)).await {
+            Ok(result) => result,
+            Err(_) => {
+                eprintln!(
+                    "Connect to {} timed out after {:?} for client {}, retrying once",
+                    addr, self.connect_timeout, self.client_addr
+                );
+                tokio::time::timeout(self.connect_timeout, TcpStream::connect(addr))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("connect to {} timed out twice", addr),
+                        ))
+                    })
+            }
         }
     }
 
+    /// Checks whether a socket popped from the idle pool is still usable. Upstreams commonly
+    /// enforce their own idle timeout and will have already closed connections sitting in the
+    /// pool; a one-byte non-blocking read distinguishes "still open, no data waiting"
+    /// (`WouldBlock`) from "closed" (`Ok(0)`), errored, or holding unsolicited data (`Ok(1)`).
+    /// Only the `WouldBlock` case counts as alive, so a byte is consumed only from a socket
+    /// that is then discarded.
+    fn pooled_socket_is_alive(socket: &TcpStream) -> bool {
+        matches!(socket.try_read(&mut [0u8; 1]), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+
+    /// Builds a PROXY protocol header describing this client's connection, to be written to the
+    /// upstream socket ahead of the forwarded request. Returns an empty buffer when disabled.
+    fn build_proxy_protocol_header(&self, upstream_addr: std::net::SocketAddr) -> Vec<u8> {
+        build_proxy_protocol_header(self.proxy_protocol_version, self.client_addr, upstream_addr)
+    }
+
     async fn send_http_error(&mut self, status_code: u16, message: &str) -> Result<(), io::Error> {
         let response_body = format!("<h1>{} {}</h1>", status_code, message);
         let response = format!(
@@ -131,17 +300,78 @@ impl ProxyConnection {
         }
     }
 
-    async fn connect_to_upstream(&mut self, _req: &http_parser::ParsedRequest) -> Result<TcpStream, ProxyError> {
-        let upstream_addr = "192.168.0.40:80";
+    /// Resolves `req`'s upstream and returns a connected socket together with the resolved
+    /// `host:port` key, so the caller can pool the socket back under the exact key it was
+    /// checked out or dialed with (an upstream's configured host may resolve to any number of
+    /// `peer_addr()` IPs, so that can't be used as the pool key on the way back in).
+    async fn connect_to_upstream(&mut self, req: &http_parser::ParsedRequest) -> Result<(TcpStream, String), ProxyError> {
+        let host = req
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+            .map(|(_, value)| value.as_str());
+
+        let upstream_addr = match self.routing_table.resolve(host, &req.path) {
+            Some(addr) => addr.to_string(),
+            None => {
+                eprintln!(
+                    "No route matched Host {:?} Path {} for client {}",
+                    host, req.path, self.client_addr
+                );
+                self.send_http_error(404, "Not Found").await.map_err(|e| eprintln!("Error sending 404: {}", e)).ok();
+                return Err(ProxyError::HttpErrorSent);
+            }
+        };
+
+        let pooled_socket = {
+            let mut pool = self.upstream_pool.lock().await;
+            let mut idle = pool.get_mut(&upstream_addr);
+            let mut live_socket = None;
+            while let Some(socket) = idle.as_deref_mut().and_then(|idle| idle.pop_front()) {
+                if Self::pooled_socket_is_alive(&socket) {
+                    live_socket = Some(socket);
+                    break;
+                }
+                println!("Discarding dead pooled connection to {} for client {}", upstream_addr, self.client_addr);
+            }
+            live_socket
+        };
+        if let Some(socket) = pooled_socket {
+            println!("Reusing pooled upstream connection to {} for client {}", upstream_addr, self.client_addr);
+            return Ok((socket, upstream_addr));
+        }
+
         println!("Connecting to upstream server: {} for client {}", upstream_addr, self.client_addr);
-        match TcpStream::connect(upstream_addr).await {
-            Ok(socket) => {
+        match self.connect_with_timeout(&upstream_addr).await {
+            Ok(mut socket) => {
                 println!("Connected to upstream for client {}", self.client_addr);
-                Ok(socket)
+                if self.proxy_protocol_version != ProxyProtocolVersion::None {
+                    // Written once, right after the socket is freshly dialed: a socket popped
+                    // from the pool already had its preamble sent the first time it was opened,
+                    // and writing it again would inject a bogus line into the middle of whatever
+                    // request is later forwarded over the reused connection.
+                    if let Ok(peer_addr) = socket.peer_addr() {
+                        let proxy_header = self.build_proxy_protocol_header(peer_addr);
+                        if let Err(e) = socket.write_all(&proxy_header).await {
+                            eprintln!(
+                                "Failed to write PROXY protocol header to upstream {} for client {}: {}",
+                                upstream_addr, self.client_addr, e
+                            );
+                            self.send_http_error(502, "Bad Gateway").await.map_err(|e| eprintln!("Error sending 502: {}", e)).ok();
+                            return Err(ProxyError::HttpErrorSent);
+                        }
+                    }
+                }
+                Ok((socket, upstream_addr))
             }
             Err(e) => {
                 eprintln!("Failed to connect to upstream server {} for client {}: {}", upstream_addr, self.client_addr, e);
-                self.send_http_error(502, "Bad Gateway").await.map_err(|e| eprintln!("Error sending 502: {}", e)).ok();
+                let (status, message) = if e.kind() == io::ErrorKind::TimedOut {
+                    (504, "Gateway Timeout")
+                } else {
+                    (502, "Bad Gateway")
+                };
+                self.send_http_error(status, message).await.map_err(|e| eprintln!("Error sending {}: {}", status, e)).ok();
                 Err(ProxyError::HttpErrorSent)
             }
         }
@@ -151,19 +381,245 @@ impl ProxyConnection {
         let request_line = format!("{} {} HTTP/1.{}\r\n", req.method, req.path, req.version);
         upstream_socket.write_all(request_line.as_bytes()).await?;
 
-        for (name, value) in &req.headers {
+        let mut headers = http_parser::strip_hop_by_hop_headers(&req.headers);
+
+        let body_framing = http_parser::determine_body_framing(&req.headers);
+        if body_framing == http_parser::BodyFraming::Chunked {
+            // strip_hop_by_hop_headers drops Transfer-Encoding as hop-by-hop, but
+            // relay_chunked_body re-emits the client's chunk framing byte-for-byte, so the
+            // upstream still needs to be told the body is chunked.
+            headers.push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+        }
+
+        let client_ip = self.client_addr.ip().to_string();
+        match headers.iter_mut().find(|(name, _)| name.eq_ignore_ascii_case("x-forwarded-for")) {
+            Some((_, value)) => *value = format!("{}, {}", value, client_ip),
+            None => headers.push(("X-Forwarded-For".to_string(), client_ip)),
+        }
+
+        if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("x-forwarded-proto")) {
+            headers.push(("X-Forwarded-Proto".to_string(), "http".to_string()));
+        }
+
+        if !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("x-forwarded-host")) {
+            if let Some((_, host)) = req.headers.iter().find(|(name, _)| name.eq_ignore_ascii_case("host")) {
+                headers.push(("X-Forwarded-Host".to_string(), host.clone()));
+            }
+        }
+
+        for (name, value) in &headers {
             let header_line = format!("{}: {}\r\n", name, value);
             upstream_socket.write_all(header_line.as_bytes()).await?;
         }
         upstream_socket.write_all(b"\r\n").await?; // End of headers
 
-        if !self.client_buffer.is_empty() { // Send remaining body from initial parse buffer
-            println!("Writing {} bytes of initial body from client_buffer to upstream for {}", self.client_buffer.len(), self.client_addr);
-            upstream_socket.write_all(&self.client_buffer).await?;
+        match body_framing {
+            http_parser::BodyFraming::ContentLength(len) => {
+                Self::relay_fixed_length_body(&mut self.client_socket, upstream_socket, &mut self.client_buffer, len).await?;
+            }
+            http_parser::BodyFraming::Chunked => {
+                Self::relay_chunked_body(&mut self.client_socket, upstream_socket, &mut self.client_buffer).await?;
+            }
+            http_parser::BodyFraming::None => {
+                // No declared body. Anything still sitting in client_buffer belongs to the next
+                // pipelined request, not this one, so it's left untouched for the next read loop.
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a response's status line, headers and body from `upstream_socket`, relaying each
+    /// piece to the client as it is read, and reports whether the upstream connection may be
+    /// pooled for reuse as far as the upstream is concerned (i.e. the response didn't carry
+    /// `Connection: close` and the body wasn't close-delimited); the client's own
+    /// `Connection: close` is checked by the caller. `request_method` is needed to recognize
+    /// bodiless `HEAD` responses.
+    async fn relay_response(&mut self, upstream_socket: &mut TcpStream, request_method: &str) -> Result<bool, ProxyError> {
+        let mut response_buffer = BytesMut::with_capacity(4096);
+        let mut awaiting_first_byte = true;
+        let parsed = loop {
+            if let Some(parsed) = http_parser::parse_http_response(&mut response_buffer)? {
+                break parsed;
+            }
+
+            // Only the wait for the first byte of the response is time-bounded: origins may
+            // legitimately take a while to start responding, then stream the rest quickly.
+            let read_result = if awaiting_first_byte {
+                match tokio::time::timeout(self.first_byte_timeout, upstream_socket.read_buf(&mut response_buffer)).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        eprintln!(
+                            "Timed out after {:?} waiting for the first byte of the response from upstream for client {}",
+                            self.first_byte_timeout, self.client_addr
+                        );
+                        self.send_http_error(504, "Gateway Timeout").await.map_err(|e| eprintln!("Error sending 504: {}", e)).ok();
+                        return Err(ProxyError::HttpErrorSent);
+                    }
+                }
+            } else {
+                upstream_socket.read_buf(&mut response_buffer).await
+            };
+            awaiting_first_byte = false;
+
+            match read_result {
+                Ok(0) => {
+                    return Err(ProxyError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "upstream closed before sending response headers",
+                    )));
+                }
+                Ok(_) => {}
+                Err(e) => return Err(ProxyError::Io(e)),
+            }
+        };
+
+        println!(
+            "Relaying response {} {} to client {}",
+            parsed.status_code, parsed.reason, self.client_addr
+        );
+
+        let status_line = format!("HTTP/1.1 {} {}\r\n", parsed.status_code, parsed.reason);
+        self.client_socket.write_all(status_line.as_bytes()).await?;
+        for (name, value) in &parsed.headers {
+            let header_line = format!("{}: {}\r\n", name, value);
+            self.client_socket.write_all(header_line.as_bytes()).await?;
+        }
+        self.client_socket.write_all(b"\r\n").await?;
+
+        let upstream_wants_close = parsed.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("connection") && value.to_ascii_lowercase().contains("close")
+        });
+
+        if http_parser::response_has_no_body(request_method, parsed.status_code) {
+            return Ok(!upstream_wants_close);
+        }
+
+        match http_parser::determine_body_framing(&parsed.headers) {
+            http_parser::BodyFraming::ContentLength(len) => {
+                Self::relay_fixed_length_body(upstream_socket, &mut self.client_socket, &mut response_buffer, len).await?;
+                Ok(!upstream_wants_close)
+            }
+            http_parser::BodyFraming::Chunked => {
+                Self::relay_chunked_body(upstream_socket, &mut self.client_socket, &mut response_buffer).await?;
+                Ok(!upstream_wants_close)
+            }
+            http_parser::BodyFraming::None => {
+                // Close-delimited body (legal for HTTP/1.0, and for HTTP/1.1 responses that omit
+                // framing): read until upstream closes the connection, then it can't be pooled.
+                Self::relay_until_close(upstream_socket, &mut self.client_socket, &mut response_buffer).await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Relays whatever is left in `leftover`, then everything `reader` sends until EOF, to
+    /// `writer`. Used for close-delimited response bodies that declare no `Content-Length` or
+    /// `Transfer-Encoding`.
+    async fn relay_until_close(
+        reader: &mut TcpStream,
+        writer: &mut TcpStream,
+        leftover: &mut BytesMut,
+    ) -> Result<(), ProxyError> {
+        if !leftover.is_empty() {
+            writer.write_all(&leftover[..]).await?;
+            leftover.clear();
+        }
+        let mut scratch = [0u8; 8192];
+        loop {
+            match reader.read(&mut scratch).await {
+                Ok(0) => return Ok(()),
+                Ok(n) => writer.write_all(&scratch[..n]).await?,
+                Err(e) => return Err(ProxyError::Io(e)),
+            }
+        }
+    }
+
+    /// Relays exactly `len` bytes of body from `reader` (starting with whatever is already
+    /// sitting in `leftover`) to `writer`. Used for both request bodies (client -> upstream) and
+    /// response bodies (upstream -> client).
+    async fn relay_fixed_length_body(
+        reader: &mut TcpStream,
+        writer: &mut TcpStream,
+        leftover: &mut BytesMut,
+        len: usize,
+    ) -> Result<(), ProxyError> {
+        let mut remaining = len;
+        while remaining > 0 {
+            if leftover.is_empty() {
+                match reader.read_buf(leftover).await {
+                    Ok(0) => {
+                        return Err(ProxyError::Io(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "connection closed mid-body",
+                        )));
+                    }
+                    Ok(_) => {}
+                    Err(e) => return Err(ProxyError::Io(e)),
+                }
+            }
+            let take = remaining.min(leftover.len());
+            writer.write_all(&leftover[..take]).await?;
+            leftover.advance(take);
+            remaining -= take;
         }
         Ok(())
     }
 
+    /// Relays a chunked body from `reader` to `writer`, re-emitting the exact chunk framing
+    /// (size lines, data and trailers) byte-for-byte so the far side sees a valid chunked
+    /// stream, while tracking the zero-size terminator so the caller knows the body is done.
+    async fn relay_chunked_body(
+        reader: &mut TcpStream,
+        writer: &mut TcpStream,
+        leftover: &mut BytesMut,
+    ) -> Result<(), ProxyError> {
+        loop {
+            let size_line = Self::read_until_crlf(reader, leftover).await?;
+            writer.write_all(&size_line).await?;
+
+            let size_str = String::from_utf8_lossy(&size_line[..size_line.len() - 2]);
+            let size_str = size_str.split(';').next().unwrap_or("").trim();
+            let chunk_size = usize::from_str_radix(size_str, 16)
+                .map_err(|e| ProxyError::Parse(Box::new(e) as Box<dyn Error + Send + Sync>))?;
+
+            if chunk_size == 0 {
+                loop {
+                    let trailer_line = Self::read_until_crlf(reader, leftover).await?;
+                    writer.write_all(&trailer_line).await?;
+                    if trailer_line.as_ref() == b"\r\n" {
+                        break;
+                    }
+                }
+                return Ok(());
+            }
+
+            Self::relay_fixed_length_body(reader, writer, leftover, chunk_size).await?;
+
+            let chunk_crlf = Self::read_until_crlf(reader, leftover).await?;
+            writer.write_all(&chunk_crlf).await?;
+        }
+    }
+
+    /// Reads from `leftover` (refilling it from `socket` as needed) until a CRLF is found,
+    /// returning the matched bytes including the trailing CRLF.
+    async fn read_until_crlf(socket: &mut TcpStream, leftover: &mut BytesMut) -> Result<BytesMut, ProxyError> {
+        loop {
+            if let Some(pos) = leftover.windows(2).position(|w| w == b"\r\n") {
+                return Ok(leftover.split_to(pos + 2));
+            }
+            match socket.read_buf(leftover).await {
+                Ok(0) => {
+                    return Err(ProxyError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed mid chunk",
+                    )));
+                }
+                Ok(_) => {}
+                Err(e) => return Err(ProxyError::Io(e)),
+            }
+        }
+    }
+
     async fn proxy_bidirectional_data(&mut self, upstream_socket: &mut TcpStream) -> Result<(), ProxyError> {
         let (mut client_read, mut client_write) = self.client_socket.split();
         let (mut upstream_read, mut upstream_write) = upstream_socket.split();
@@ -187,25 +643,147 @@ impl ProxyConnection {
         }
     }
 
+    async fn handle_connect(&mut self, req: &http_parser::ParsedRequest) -> Result<(), ProxyError> {
+        let target_addr = req.path.as_str();
+        println!("Establishing CONNECT tunnel to {} for client {}", target_addr, self.client_addr);
+
+        let mut upstream_socket = match self.connect_with_timeout(target_addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("Failed to connect to CONNECT target {} for client {}: {}", target_addr, self.client_addr, e);
+                let (status, message) = if e.kind() == io::ErrorKind::TimedOut {
+                    (504, "Gateway Timeout")
+                } else {
+                    (502, "Bad Gateway")
+                };
+                self.send_http_error(status, message).await.map_err(|e| eprintln!("Error sending {}: {}", status, e)).ok();
+                return Err(ProxyError::HttpErrorSent);
+            }
+        };
+
+        self.client_socket
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+
+        if !self.client_buffer.is_empty() {
+            println!(
+                "Flushing {} pipelined bytes already buffered from {} to upstream before tunneling",
+                self.client_buffer.len(),
+                self.client_addr
+            );
+            upstream_socket.write_all(&self.client_buffer).await?;
+            self.client_buffer.clear();
+        }
+
+        self.proxy_bidirectional_data(&mut upstream_socket).await
+    }
+
     pub async fn handle_request(&mut self) -> Result<(), ProxyError> {
-        let req = self.read_and_parse_request().await?;
-        
-        let mut upstream_socket = self.connect_to_upstream(&req).await?;
-        
-        match self.forward_request_to_upstream(&mut upstream_socket, &req).await {
-            Ok(_) => {
-            }
-            Err(forward_err) => { 
-                eprintln!("Error forwarding request to upstream for {}: {}", self.client_addr, forward_err);
-                if let Err(e) = self.send_http_error(502, "Bad Gateway (Upstream Write Error)").await {
-                    eprintln!("Error sending 502 to client {}: {}", self.client_addr, e);
+        loop {
+            let req = self.read_and_parse_request().await?;
+
+            if req.method.eq_ignore_ascii_case("CONNECT") {
+                return self.handle_connect(&req).await;
+            }
+
+            let client_wants_keep_alive = !req.headers.iter().any(|(name, value)| {
+                name.eq_ignore_ascii_case("connection") && value.to_ascii_lowercase().contains("close")
+            });
+
+            let (mut upstream_socket, upstream_key) = self.connect_to_upstream(&req).await?;
+
+            match self.forward_request_to_upstream(&mut upstream_socket, &req).await {
+                Ok(_) => {
                 }
-                return Err(ProxyError::HttpErrorSent); 
+                Err(forward_err) => {
+                    eprintln!("Error forwarding request to upstream for {}: {}", self.client_addr, forward_err);
+                    if let Err(e) = self.send_http_error(502, "Bad Gateway (Upstream Write Error)").await {
+                        eprintln!("Error sending 502 to client {}: {}", self.client_addr, e);
+                    }
+                    return Err(ProxyError::HttpErrorSent);
+                }
+            }
+
+            let upstream_keep_alive = self.relay_response(&mut upstream_socket, &req.method).await?;
+
+            if upstream_keep_alive && client_wants_keep_alive {
+                println!("Returning upstream connection to {} to the pool for reuse", upstream_key);
+                self.upstream_pool.lock().await.entry(upstream_key).or_insert_with(VecDeque::new).push_back(upstream_socket);
+            } else {
+                return Ok(());
             }
         }
+    }
+}
 
-        self.proxy_bidirectional_data(&mut upstream_socket).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
 
-        Ok(())
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn proxy_protocol_header_none_is_empty() {
+        let header = build_proxy_protocol_header(
+            ProxyProtocolVersion::None,
+            addr("127.0.0.1:45352"),
+            addr("127.0.0.1:9091"),
+        );
+        assert!(header.is_empty());
+    }
+
+    #[test]
+    fn proxy_protocol_v1_ipv4() {
+        let header = build_proxy_protocol_header(
+            ProxyProtocolVersion::V1,
+            addr("127.0.0.1:45352"),
+            addr("10.0.0.1:9091"),
+        );
+        assert_eq!(header, b"PROXY TCP4 127.0.0.1 10.0.0.1 45352 9091\r\n");
+    }
+
+    #[test]
+    fn proxy_protocol_v1_ipv6() {
+        let header = build_proxy_protocol_header(
+            ProxyProtocolVersion::V1,
+            addr("[::1]:45352"),
+            addr("[::2]:9091"),
+        );
+        assert_eq!(header, b"PROXY TCP6 ::1 ::2 45352 9091\r\n");
+    }
+
+    #[test]
+    fn proxy_protocol_v2_ipv4_header_layout() {
+        let header = build_proxy_protocol_header(
+            ProxyProtocolVersion::V2,
+            addr("127.0.0.1:45352"),
+            addr("10.0.0.1:9091"),
+        );
+        assert_eq!(
+            &header[..12],
+            &[0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A]
+        );
+        assert_eq!(header[12], 0x21); // version 2, PROXY command
+        assert_eq!(header[13], 0x11); // AF_INET, STREAM
+        assert_eq!(&header[14..16], &12u16.to_be_bytes());
+        assert_eq!(&header[16..20], &[127, 0, 0, 1]);
+        assert_eq!(&header[20..24], &[10, 0, 0, 1]);
+        assert_eq!(&header[24..26], &45352u16.to_be_bytes());
+        assert_eq!(&header[26..28], &9091u16.to_be_bytes());
+    }
+
+    #[test]
+    fn proxy_protocol_v2_mixed_families_falls_back_to_local() {
+        let header = build_proxy_protocol_header(
+            ProxyProtocolVersion::V2,
+            addr("127.0.0.1:45352"),
+            addr("[::2]:9091"),
+        );
+        assert_eq!(header[12], 0x20); // version 2, LOCAL command
+        assert_eq!(header[13], 0x00); // AF_UNSPEC, UNSPEC
+        assert_eq!(&header[14..16], &0u16.to_be_bytes());
     }
 }
@@ -0,0 +1,111 @@
+use serde::Deserialize;
+use std::fs;
+use std::sync::Arc;
+
+/// A single routing rule: an optional Host pattern (supports a `*.` wildcard prefix) and/or an
+/// optional path prefix, both of which must match for `upstream` to be selected.
+#[derive(Debug, Deserialize)]
+pub struct RouteRule {
+    pub host: Option<String>,
+    pub path_prefix: Option<String>,
+    pub upstream: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoutingConfig {
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    pub default_upstream: Option<String>,
+}
+
+/// Resolves an upstream `host:port` target for an incoming request from a routing table loaded
+/// once at startup and shared (via `Arc`) across every spawned connection task.
+pub struct RoutingTable {
+    config: RoutingConfig,
+}
+
+impl RoutingTable {
+    /// Loads a routing table from a TOML or JSON file, selected by file extension.
+    pub fn load(path: &str) -> Result<Arc<Self>, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        let config: RoutingConfig = if path.ends_with(".json") {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        Ok(Arc::new(Self { config }))
+    }
+
+    /// Builds a routing table with no rules beyond a single default upstream, matching the
+    /// crate's previous hardcoded-upstream behavior.
+    pub fn single_upstream(upstream: &str) -> Arc<Self> {
+        Arc::new(Self {
+            config: RoutingConfig {
+                routes: Vec::new(),
+                default_upstream: Some(upstream.to_string()),
+            },
+        })
+    }
+
+    /// Picks the upstream `host:port` for a request's `Host` header and path, falling back to
+    /// the configured default upstream. Returns `None` when nothing matches.
+    pub fn resolve(&self, host: Option<&str>, path: &str) -> Option<&str> {
+        for rule in &self.config.routes {
+            let host_matches = match (&rule.host, host) {
+                (Some(pattern), Some(actual)) => host_matches_pattern(pattern, actual),
+                (None, _) => true,
+                (Some(_), None) => false,
+            };
+            let path_matches = match &rule.path_prefix {
+                Some(prefix) => path.starts_with(prefix.as_str()),
+                None => true,
+            };
+            if host_matches && path_matches {
+                return Some(rule.upstream.as_str());
+            }
+        }
+        self.config.default_upstream.as_deref()
+    }
+}
+
+fn host_matches_pattern(pattern: &str, host: &str) -> bool {
+    let host = host.split(':').next().unwrap_or(host); // strip any port suffix
+    match pattern.strip_prefix("*.") {
+        // Match only true subdomains: require the separating dot so "*.example.com" doesn't
+        // also match "evilexample.com".
+        Some(suffix) => host.to_ascii_lowercase().ends_with(&format!(".{}", suffix.to_ascii_lowercase())),
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_pattern_matches_case_insensitively() {
+        assert!(host_matches_pattern("Example.com", "example.COM"));
+        assert!(!host_matches_pattern("example.com", "other.com"));
+    }
+
+    #[test]
+    fn exact_pattern_ignores_port_suffix_on_host() {
+        assert!(host_matches_pattern("example.com", "example.com:8080"));
+    }
+
+    #[test]
+    fn wildcard_matches_true_subdomain() {
+        assert!(host_matches_pattern("*.example.com", "api.example.com"));
+        assert!(host_matches_pattern("*.example.com", "API.Example.Com"));
+    }
+
+    #[test]
+    fn wildcard_does_not_match_suffix_without_dot_boundary() {
+        assert!(!host_matches_pattern("*.example.com", "evilexample.com"));
+    }
+
+    #[test]
+    fn wildcard_does_not_match_bare_domain() {
+        assert!(!host_matches_pattern("*.example.com", "example.com"));
+    }
+}